@@ -0,0 +1,159 @@
+//! Result cache sitting between the context protocol and the
+//! `service`/`worker` dispatch layer, so repeated requests for the same
+//! idempotent `{service, action, payload}` can be served without
+//! re-running the worker.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use session::Value;
+
+/// Pluggable backing store for cached action results.
+pub trait CacheAdapter: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>);
+
+    /// Drops every cached entry whose key matches `pattern`. `pattern`
+    /// may contain `*` wildcards (e.g. `"accounts:*"` to invalidate
+    /// every cached action of the `accounts` service), or be a plain
+    /// prefix with no wildcard at all.
+    fn invalidate(&self, pattern: &str);
+}
+
+/// Derives a cache key from the parts of an `Input::Request`. Keeping
+/// `service`/`action` as a literal prefix lets callers `invalidate`
+/// with a glob like `"accounts:*"` without needing to know the hash.
+pub fn key_for(service: &str, action: &str, payload: &Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    payload.to_string().hash(&mut hasher);
+    format!("{}:{}:{:x}", service, action, hasher.finish())
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+/// An embedded, process-local `CacheAdapter`.
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        MemoryCache::default()
+    }
+}
+
+impl CacheAdapter for MemoryCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) => {
+                if entry.expires_at.map_or(false, |at| Instant::now() >= at) {
+                    entries.remove(key);
+                    None
+                } else {
+                    Some(entry.value.clone())
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let entry = Entry {
+            value: value,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        self.entries.lock().unwrap().insert(key.to_owned(), entry);
+    }
+
+    fn invalidate(&self, pattern: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| !glob_match(pattern, key));
+    }
+}
+
+fn glob_match(pattern: &str, candidate: &str) -> bool {
+    // A pattern with no wildcard at all is documented to behave as a
+    // plain prefix match (e.g. invalidate("accounts") should drop
+    // every key the accounts service wrote), not an exact match.
+    if !pattern.contains('*') {
+        return candidate.starts_with(pattern);
+    }
+
+    fn matches(pattern: &[u8], candidate: &[u8]) -> bool {
+        match pattern.first() {
+            None => candidate.is_empty(),
+            Some(&b'*') => {
+                matches(&pattern[1..], candidate)
+                    || (!candidate.is_empty() && matches(pattern, &candidate[1..]))
+            }
+            Some(&byte) => {
+                candidate.first() == Some(&byte) && matches(&pattern[1..], &candidate[1..])
+            }
+        }
+    }
+    matches(pattern.as_bytes(), candidate.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn wildcard_less_pattern_matches_as_a_prefix() {
+        assert!(glob_match("accounts", "accounts:list:deadbeef"));
+        assert!(!glob_match("accounts", "billing:list:deadbeef"));
+    }
+
+    #[test]
+    fn trailing_wildcard_matches_everything_after_the_prefix() {
+        assert!(glob_match("accounts:*", "accounts:list:deadbeef"));
+        assert!(!glob_match("accounts:*", "billing:list:deadbeef"));
+    }
+
+    #[test]
+    fn wildcard_in_the_middle_matches_across_the_gap() {
+        assert!(glob_match("accounts:*:deadbeef", "accounts:list:deadbeef"));
+        assert!(!glob_match("accounts:*:deadbeef", "accounts:list:cafebabe"));
+    }
+
+    #[test]
+    fn exact_pattern_with_no_wildcard_is_still_a_match_for_itself() {
+        assert!(glob_match("accounts:list:deadbeef", "accounts:list:deadbeef"));
+    }
+
+    #[test]
+    fn memory_cache_get_expires_entries_past_their_ttl() {
+        let cache = MemoryCache::new();
+        cache.set("k", b"v".to_vec(), Some(Duration::from_millis(0)));
+        ::std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get("k"), None);
+    }
+
+    #[test]
+    fn memory_cache_get_keeps_entries_with_no_ttl() {
+        let cache = MemoryCache::new();
+        cache.set("k", b"v".to_vec(), None);
+        assert_eq!(cache.get("k"), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn memory_cache_invalidate_drops_matching_keys_only() {
+        let cache = MemoryCache::new();
+        cache.set("accounts:list:a", b"1".to_vec(), None);
+        cache.set("billing:list:b", b"2".to_vec(), None);
+        cache.invalidate("accounts:*");
+        assert_eq!(cache.get("accounts:list:a"), None);
+        assert_eq!(cache.get("billing:list:b"), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,45 @@
+//! Pluggable wire encoding for `Context`.
+//!
+//! `Context` talks to its `Flow` in raw bytes; a `Codec` is what turns
+//! those bytes into `Input`/`Output` values and back, so the protocol
+//! itself isn't hard-wired to textual JSON.
+
+use session::{Error, Input, Output, Result};
+
+pub trait Codec: Send + Sync + 'static {
+    fn decode(&self, bytes: &[u8]) -> Result<Input>;
+    fn encode(&self, out: &Output) -> Result<Vec<u8>>;
+}
+
+/// The default codec, preserving the JSON wire format `Context` has
+/// always used.
+#[derive(Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn decode(&self, bytes: &[u8]) -> Result<Input> {
+        Ok(::serde_json::from_slice(bytes)?)
+    }
+
+    fn encode(&self, out: &Output) -> Result<Vec<u8>> {
+        Ok(::serde_json::to_vec(out)?)
+    }
+}
+
+/// A compact binary codec for internal, high-throughput links where
+/// both ends are trusted services and JSON's parsing cost isn't worth
+/// paying.
+#[cfg(feature = "msgpack")]
+#[derive(Default)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPackCodec {
+    fn decode(&self, bytes: &[u8]) -> Result<Input> {
+        ::rmp_serde::from_slice(bytes).map_err(|cause| Error::CodecFailed(cause.to_string()))
+    }
+
+    fn encode(&self, out: &Output) -> Result<Vec<u8>> {
+        ::rmp_serde::to_vec(out).map_err(|cause| Error::CodecFailed(cause.to_string()))
+    }
+}
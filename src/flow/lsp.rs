@@ -0,0 +1,169 @@
+//! `Flow` implementation that frames messages the way the Language
+//! Server Protocol does: a `Content-Length: N\r\n\r\n` header block
+//! followed by exactly `N` bytes of body. This lets `Context` run over
+//! plain stdio or a raw TCP stream instead of a websocket, which is
+//! what embedding `mould` as a subprocess needs.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use flow::{Error, Flow, Result};
+
+const CONTENT_LENGTH: &str = "Content-Length";
+
+/// Upper bound on a single framed message. Guards against a
+/// missing/malformed peer (or a corrupt subprocess pipe) sending a
+/// `Content-Length` so large that allocating a buffer for it would
+/// abort the process, long before we get a chance to read (or reject)
+/// the body.
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Frames messages over any `Read + Write` pair, such as
+/// `(stdin, stdout)` or a `TcpStream`.
+pub struct LspFlow<I: Read, O: Write> {
+    input: BufReader<I>,
+    output: O,
+}
+
+impl<I: Read, O: Write> LspFlow<I, O> {
+    pub fn new(input: I, output: O) -> Self {
+        LspFlow {
+            input: BufReader::new(input),
+            output: output,
+        }
+    }
+
+    fn read_content_length(&mut self) -> Result<Option<usize>> {
+        let mut content_length = None;
+        let mut header_started = false;
+
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // Peer closed before sending a header block at all:
+                // that's only a graceful close if it happened right at
+                // a message boundary, i.e. no header was read yet.
+                return if !header_started {
+                    Ok(None)
+                } else {
+                    Err(Error::ConnectionClosed)
+                };
+            }
+            header_started = true;
+
+            let line = line.trim_end_matches(|c| c == '\r' || c == '\n').to_owned();
+            if line.is_empty() {
+                break;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            if name.eq_ignore_ascii_case(CONTENT_LENGTH) {
+                let parsed: usize = value.parse().map_err(|_| {
+                    Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid Content-Length header",
+                    ))
+                })?;
+                if parsed > MAX_CONTENT_LENGTH {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Content-Length exceeds maximum allowed message size",
+                    )));
+                }
+                content_length = Some(parsed);
+            }
+        }
+
+        content_length.map(Some).ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing Content-Length header",
+            ))
+        })
+    }
+}
+
+impl<I: Read + Send + 'static, O: Write + Send + 'static> Flow for LspFlow<I, O> {
+    fn pull(&mut self) -> Result<Option<Vec<u8>>> {
+        let content_length = match self.read_content_length()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut body = vec![0u8; content_length];
+        self.input.read_exact(&mut body).map_err(|cause| {
+            if cause.kind() == io::ErrorKind::UnexpectedEof {
+                Error::ConnectionClosed
+            } else {
+                Error::Io(cause)
+            }
+        })?;
+
+        Ok(Some(body))
+    }
+
+    fn push(&mut self, content: Vec<u8>) -> Result<()> {
+        write!(self.output, "{}: {}\r\n\r\n", CONTENT_LENGTH, content.len())?;
+        self.output.write_all(&content)?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn flow(input: &[u8]) -> LspFlow<Cursor<Vec<u8>>, Vec<u8>> {
+        LspFlow::new(Cursor::new(input.to_vec()), Vec::new())
+    }
+
+    #[test]
+    fn pulls_a_framed_message() {
+        let mut flow = flow(b"Content-Length: 5\r\n\r\nhello");
+        assert_eq!(flow.pull().unwrap(), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn pull_returns_none_at_a_clean_message_boundary() {
+        let mut flow = flow(b"");
+        assert_eq!(flow.pull().unwrap(), None);
+    }
+
+    #[test]
+    fn pull_rejects_a_missing_content_length_header() {
+        let mut flow = flow(b"X-Other: 1\r\n\r\nhello");
+        assert!(flow.pull().is_err());
+    }
+
+    #[test]
+    fn pull_rejects_an_invalid_content_length_header() {
+        let mut flow = flow(b"Content-Length: not-a-number\r\n\r\n");
+        assert!(flow.pull().is_err());
+    }
+
+    #[test]
+    fn pull_rejects_a_content_length_over_the_maximum_instead_of_allocating_it() {
+        let mut flow = flow(b"Content-Length: 999999999999\r\n\r\n");
+        assert!(flow.pull().is_err());
+    }
+
+    #[test]
+    fn pull_reports_connection_closed_on_a_truncated_body() {
+        let mut flow = flow(b"Content-Length: 10\r\n\r\nabc");
+        match flow.pull() {
+            Err(Error::ConnectionClosed) => {}
+            other => panic!("expected ConnectionClosed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn push_writes_the_header_and_body() {
+        let mut flow = flow(b"");
+        flow.push(b"hi".to_vec()).unwrap();
+        assert_eq!(flow.output, b"Content-Length: 2\r\n\r\nhi".to_vec());
+    }
+}
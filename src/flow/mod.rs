@@ -0,0 +1,47 @@
+//! Flow module abstracts the transport that carries raw protocol
+//! messages between a `Context` and its connected client.
+
+#[cfg(feature = "wsmould")]
+pub mod wsmould;
+pub mod lsp;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "connection closed")]
+    ConnectionClosed,
+    #[cfg(feature = "wsmould")]
+    #[fail(display = "websocket error")]
+    WebSocket(#[cause] ::websocket::WebSocketError),
+    #[fail(display = "io error")]
+    Io(#[cause] ::std::io::Error),
+}
+
+#[cfg(feature = "wsmould")]
+impl From<::websocket::WebSocketError> for Error {
+    fn from(cause: ::websocket::WebSocketError) -> Self {
+        Error::WebSocket(cause)
+    }
+}
+
+impl From<::std::io::Error> for Error {
+    fn from(cause: ::std::io::Error) -> Self {
+        Error::Io(cause)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// A transport able to pull/push whole protocol messages as raw bytes.
+///
+/// Carrying bytes rather than `String` lets a `Flow` stay agnostic to
+/// whatever `Codec` `Context` is using above it, including binary ones.
+///
+/// `pull` returns `Ok(None)` when the peer closed the connection
+/// gracefully. A dead peer detected by a heartbeat, or a stream that
+/// ends partway through a message, is reported as
+/// `Err(Error::ConnectionClosed)`; other transport failures surface as
+/// `Error::Io` or `Error::WebSocket`.
+pub trait Flow: Send + 'static {
+    fn pull(&mut self) -> Result<Option<Vec<u8>>>;
+    fn push(&mut self, content: Vec<u8>) -> Result<()>;
+}
@@ -0,0 +1,221 @@
+//! `Flow` implementation over the `websocket` crate, with a heartbeat
+//! that notices a half-open peer instead of blocking `Context::recv`
+//! forever.
+
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use websocket::sync::Client;
+use websocket::{Message, OwnedMessage};
+
+use flow::{Error, Flow, Result};
+
+/// Tunables for the websocket heartbeat.
+///
+/// A Ping frame is sent every `ping_interval`; if neither a Pong nor any
+/// other traffic arrives within the following `ping_timeout` the peer is
+/// treated as dead. `close_timeout` bounds how long a graceful Close
+/// handshake is allowed to take so shutdown can't hang on an
+/// unresponsive peer either.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    pub close_timeout: Duration,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            ping_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(3),
+            close_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl KeepAlive {
+    /// Turns the heartbeat off: pings are never sent and a silent peer
+    /// is never treated as dead.
+    pub fn disabled() -> Self {
+        KeepAlive {
+            ping_interval: Duration::from_secs(0),
+            ..Default::default()
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.ping_interval > Duration::from_secs(0)
+    }
+
+    /// Decides what the heartbeat should do after the connection has
+    /// been idle for `idle`, given whether a ping is already outstanding.
+    /// Pulled out of `WsFlow::pull`'s loop so the timing thresholds can
+    /// be unit tested without a real socket.
+    fn heartbeat_action(&self, idle: Duration, ping_outstanding: bool) -> HeartbeatAction {
+        if !self.enabled() {
+            HeartbeatAction::Continue
+        } else if ping_outstanding && idle >= self.ping_interval + self.ping_timeout {
+            HeartbeatAction::Dead
+        } else if !ping_outstanding && idle >= self.ping_interval {
+            HeartbeatAction::SendPing
+        } else {
+            HeartbeatAction::Continue
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum HeartbeatAction {
+    Continue,
+    SendPing,
+    Dead,
+}
+
+pub struct WsFlow {
+    client: Client<TcpStream>,
+    keepalive: KeepAlive,
+    last_seen: Instant,
+    last_ping_sent: Option<Instant>,
+}
+
+impl WsFlow {
+    pub fn new(client: Client<TcpStream>) -> Self {
+        Self::with_keepalive(client, KeepAlive::default())
+    }
+
+    pub fn with_keepalive(client: Client<TcpStream>, keepalive: KeepAlive) -> Self {
+        WsFlow {
+            client: client,
+            keepalive: keepalive,
+            last_seen: Instant::now(),
+            last_ping_sent: None,
+        }
+    }
+
+    /// Sends a Close frame and waits up to `close_timeout` for the
+    /// peer's reply, ignoring whatever comes back: the socket is being
+    /// torn down either way.
+    fn close_gracefully(&mut self) {
+        let _ = self.client.send_message(&Message::close());
+        let _ = self.client.stream_ref().set_read_timeout(Some(self.keepalive.close_timeout));
+        let _ = self.client.recv_message();
+    }
+}
+
+impl Flow for WsFlow {
+    fn pull(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.keepalive.enabled() {
+            self.client
+                .stream_ref()
+                .set_read_timeout(Some(self.keepalive.ping_timeout))?;
+        }
+
+        loop {
+            let idle = self.last_seen.elapsed();
+            match self.keepalive.heartbeat_action(idle, self.last_ping_sent.is_some()) {
+                HeartbeatAction::Dead => {
+                    self.close_gracefully();
+                    return Err(Error::ConnectionClosed);
+                }
+                HeartbeatAction::SendPing => {
+                    self.client.send_message(&Message::ping(Vec::new()))?;
+                    self.last_ping_sent = Some(Instant::now());
+                }
+                HeartbeatAction::Continue => {}
+            }
+
+            match self.client.recv_message() {
+                Ok(OwnedMessage::Text(text)) => {
+                    self.last_seen = Instant::now();
+                    self.last_ping_sent = None;
+                    return Ok(Some(text.into_bytes()));
+                }
+                Ok(OwnedMessage::Binary(bytes)) => {
+                    self.last_seen = Instant::now();
+                    self.last_ping_sent = None;
+                    return Ok(Some(bytes));
+                }
+                Ok(OwnedMessage::Ping(payload)) => {
+                    self.last_seen = Instant::now();
+                    self.client.send_message(&Message::pong(payload))?;
+                }
+                Ok(OwnedMessage::Pong(_)) => {
+                    self.last_seen = Instant::now();
+                    self.last_ping_sent = None;
+                }
+                Ok(OwnedMessage::Close(_)) => {
+                    let _ = self.client.send_message(&Message::close());
+                    return Ok(None);
+                }
+                Err(::websocket::WebSocketError::IoError(ref cause))
+                    if cause.kind() == ::std::io::ErrorKind::WouldBlock
+                        || cause.kind() == ::std::io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(cause) => return Err(Error::from(cause)),
+            }
+        }
+    }
+
+    fn push(&mut self, content: Vec<u8>) -> Result<()> {
+        // Keep sending Text frames for textual codecs like JSON so the
+        // wire format doesn't change for existing clients; anything
+        // that isn't valid UTF-8 (a binary codec's output) goes out as
+        // a Binary frame instead.
+        let message = match String::from_utf8(content) {
+            Ok(text) => Message::text(text),
+            Err(cause) => Message::binary(cause.into_bytes()),
+        };
+        self.client.send_message(&message).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_keepalive_never_acts() {
+        let keepalive = KeepAlive::disabled();
+        assert_eq!(
+            keepalive.heartbeat_action(Duration::from_secs(10_000), false),
+            HeartbeatAction::Continue
+        );
+    }
+
+    #[test]
+    fn pings_once_idle_reaches_the_interval() {
+        let keepalive = KeepAlive::default();
+        assert_eq!(
+            keepalive.heartbeat_action(keepalive.ping_interval, false),
+            HeartbeatAction::SendPing
+        );
+    }
+
+    #[test]
+    fn does_not_send_a_second_ping_while_one_is_outstanding() {
+        let keepalive = KeepAlive::default();
+        assert_eq!(
+            keepalive.heartbeat_action(keepalive.ping_interval, true),
+            HeartbeatAction::Continue
+        );
+    }
+
+    #[test]
+    fn is_dead_once_idle_passes_interval_plus_timeout() {
+        let keepalive = KeepAlive::default();
+        assert_eq!(
+            keepalive.heartbeat_action(keepalive.ping_interval + keepalive.ping_timeout, true),
+            HeartbeatAction::Dead
+        );
+    }
+
+    #[test]
+    fn is_not_yet_dead_with_an_outstanding_ping_inside_the_timeout() {
+        let keepalive = KeepAlive::default();
+        let idle = keepalive.ping_interval + keepalive.ping_timeout - Duration::from_millis(1);
+        assert_eq!(keepalive.heartbeat_action(idle, true), HeartbeatAction::Continue);
+    }
+}
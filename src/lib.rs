@@ -10,9 +10,12 @@ pub mod macros;
 pub mod service;
 pub mod worker;
 pub mod session;
+#[cfg(feature = "wsmould")]
 pub mod server;
 pub mod prelude;
 pub mod flow;
+pub mod codec;
+pub mod cache;
 
 pub use session::Session;
 pub use session::Builder;
@@ -0,0 +1,61 @@
+//! Server module owns the TCP accept loop and wires each inbound
+//! connection through the configured `Flow` into a `Context`.
+
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::thread;
+
+use websocket::sync::Server as WsServer;
+
+use flow::wsmould::{KeepAlive, WsFlow};
+use session::{Builder, Context, Session};
+use worker::{self, Dispatch};
+
+/// Binds a websocket listener and hands every accepted connection, once
+/// upgraded and handshaken into a `Context`, to `worker::run` on its own
+/// thread together with the shared `Dispatch`.
+pub struct Server<T: Session, B: Builder<T>> {
+    builder: B,
+    keepalive: KeepAlive,
+    _session: ::std::marker::PhantomData<T>,
+}
+
+impl<T: Session, B: Builder<T>> Server<T, B> {
+    pub fn new(builder: B) -> Self {
+        Server::with_keepalive(builder, KeepAlive::default())
+    }
+
+    pub fn with_keepalive(builder: B, keepalive: KeepAlive) -> Self {
+        Server {
+            builder: builder,
+            keepalive: keepalive,
+            _session: ::std::marker::PhantomData,
+        }
+    }
+
+    pub fn listen<A, D>(&self, addr: A, dispatch: D) -> ::std::io::Result<()>
+    where
+        A: ToSocketAddrs,
+        D: Dispatch,
+        T: Send,
+    {
+        let ws_server = WsServer::bind(addr)?;
+        let dispatch = Arc::new(dispatch);
+
+        for connection in ws_server.filter_map(Result::ok) {
+            let client = match connection.accept() {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let flow = WsFlow::with_keepalive(client, self.keepalive);
+            let ctx: Context<T, WsFlow> = match Context::handshake(flow, &self.builder) {
+                Ok(ctx) => ctx,
+                Err(_) => continue,
+            };
+            let dispatch = dispatch.clone();
+            thread::spawn(move || worker::run(ctx, dispatch));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+//! Defines the services a `Worker` dispatches `request`s to.
+
+use std::time::Duration;
+
+use session::{self, Value};
+
+/// One named, invokable unit of work a client can `request`.
+pub trait Service: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    /// How long a successful result for `action` may be served from
+    /// cache instead of re-running it, or `None` (the default) if the
+    /// action isn't safe to cache, e.g. because it has side effects.
+    fn cache_ttl(&self, action: &str) -> Option<Duration> {
+        let _ = action;
+        None
+    }
+
+    /// Runs `action` with `payload`, returning the items to stream back
+    /// to the client as `Output::Item`s before the closing `Done`.
+    fn call(&self, action: &str, payload: Value) -> session::Result<Vec<Value>>;
+}
@@ -1,16 +1,25 @@
 //! Context module contains protocol implementation.
 //!
-//! Server can receive the following messages from clients:
+//! Several tasks can be in flight at once over a single connection, each
+//! identified by a `TaskId` chosen by the client. The very first
+//! message on a connection must be `init`, carrying whatever auth or
+//! session data the client wants handed to the `Builder`; only after
+//! that handshake completes can the client send `request`, `next` and
+//! `cancel`:
 //!
-//! * {"event": "request", "data": {"action": "what_to_do", "payload": {...}}}
-//! * {"event": "next"}
-//! * {"event": "cancel"}
+//! * {"event": "init", "data": {...}}
+//! * {"event": "request", "data": {"id": 1, "service": "service_name", "action": "what_to_do", "payload": {...}}}
+//! * {"event": "next", "data": {"id": 1}}
+//! * {"event": "cancel", "data": {"id": 1}}
 //!
-//! Server responds to clients the following messages:
+//! Server responds to clients the following messages; `ready` and
+//! `reject` answer the handshake, the rest are tagged with the `id` of
+//! the task they belong to:
 //!
 //! * {"event": "ready"}
-//! * {"event": "item"}
-//! * {"event": "done"}
+//! * {"event": "item", "data": {"id": 1, "payload": {...}}}
+//! * {"event": "done", "data": {"id": 1}}
+//! * {"event": "fail", "data": {"id": 1, "message": "text_of_message"}}
 //! * {"event": "reject", "data": {"message": "text_of_message"}}
 
 use std::str;
@@ -18,28 +27,39 @@ use std::default::Default;
 use std::ops::{Deref, DerefMut};
 use serde_json;
 pub use serde_json::Value;
+use codec::{Codec, JsonCodec};
 use flow::{self, Flow};
 
 /// Builds user's session and attaches resources like:
 /// database connections, channels, counters.
+///
+/// `init` is whatever payload the client sent with its `init` message,
+/// letting a `Builder` carry authenticated identity, tenant info, or
+/// per-connection limits into the session it constructs. Returning
+/// `Err` rejects the connection before any task can run on it.
 pub trait Builder<T: Session>: Send + Sync + 'static {
-    fn build(&self) -> T;
+    fn build(&self, init: Value) -> Result<T>;
 }
 
 pub struct DefaultBuilder;
 
 impl<T: Session + Default> Builder<T> for DefaultBuilder {
-    fn build(&self) -> T {
-        T::default()
+    fn build(&self, _init: Value) -> Result<T> {
+        Ok(T::default())
     }
 }
 
 pub trait Session: 'static {}
 
 /// Binds client connection instance to session
-pub struct Context<T: Session, R: Flow> {
+///
+/// `C` is the wire codec used to turn bytes from `R` into `Input`/
+/// `Output` values; it defaults to `JsonCodec` so existing callers that
+/// only name `Context<T, R>` keep today's JSON wire format.
+pub struct Context<T: Session, R: Flow, C: Codec = JsonCodec> {
     client: R,
     session: T,
+    codec: C,
 }
 
 pub type Request = Value;
@@ -47,17 +67,31 @@ pub type Request = Value;
 pub type TaskId = usize;
 
 #[derive(Serialize, Deserialize)]
-pub struct Input {
-    pub service: String,
-    pub action: String,
-    pub payload: Value,
+#[serde(tag = "event", content = "data", rename_all = "lowercase")]
+pub enum Input {
+    Init(Value),
+    Request {
+        id: TaskId,
+        service: String,
+        action: String,
+        payload: Value,
+    },
+    Next {
+        id: TaskId,
+    },
+    Cancel {
+        id: TaskId,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "event", content = "data", rename_all = "lowercase")]
 pub enum Output {
-    Item(Value),
-    Fail(String),
+    Ready,
+    Reject { message: String },
+    Item { id: TaskId, payload: Value },
+    Fail { id: TaskId, message: String },
+    Done { id: TaskId },
 }
 
 #[derive(Debug, Fail)]
@@ -68,15 +102,22 @@ pub enum Error {
     UnexpectedState,
     #[fail(display = "canceled")]
     Canceled,
+    #[fail(display = "rejected: {}", _0)]
+    Rejected(String),
     #[fail(display = "flow error")]
     FlowBroken(#[cause] flow::Error),
     #[fail(display = "serde error")]
     SerdeFailed(#[cause] serde_json::Error),
+    #[fail(display = "codec error: {}", _0)]
+    CodecFailed(String),
 }
 
 impl From<flow::Error> for Error {
     fn from(cause: flow::Error) -> Self {
-        Error::FlowBroken(cause)
+        match cause {
+            flow::Error::ConnectionClosed => Error::ConnectionClosed,
+            other => Error::FlowBroken(other),
+        }
     }
 }
 
@@ -88,7 +129,7 @@ impl From<serde_json::Error> for Error {
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-impl<T: Session, R: Flow> Deref for Context<T, R> {
+impl<T: Session, R: Flow, C: Codec> Deref for Context<T, R, C> {
     type Target = T;
 
     fn deref<'a>(&'a self) -> &'a T {
@@ -96,32 +137,178 @@ impl<T: Session, R: Flow> Deref for Context<T, R> {
     }
 }
 
-impl<T: Session, R: Flow> DerefMut for Context<T, R> {
+impl<T: Session, R: Flow, C: Codec> DerefMut for Context<T, R, C> {
     fn deref_mut<'a>(&'a mut self) -> &'a mut T {
         &mut self.session
     }
 }
 
-impl<T: Session, R: Flow> Context<T, R> {
+impl<T: Session, R: Flow, C: Codec + Default> Context<T, R, C> {
     pub fn new(client: R, session: T) -> Self {
+        Context::with_codec(client, session, C::default())
+    }
+
+    /// Performs the connection-init handshake: waits for the client's
+    /// `init` message, hands its payload to `builder`, and replies
+    /// `ready` or `reject` depending on the outcome.
+    pub fn handshake<B: Builder<T>>(client: R, builder: &B) -> Result<Self> {
+        Self::handshake_with_codec(client, builder, C::default())
+    }
+}
+
+impl<T: Session, R: Flow, C: Codec> Context<T, R, C> {
+    pub fn with_codec(client: R, session: T, codec: C) -> Self {
         Context {
             client: client,
             session: session,
+            codec: codec,
+        }
+    }
+
+    pub fn handshake_with_codec<B: Builder<T>>(mut client: R, builder: &B, codec: C) -> Result<Self> {
+        let bytes = client.pull()?.ok_or(Error::ConnectionClosed)?;
+        let input = codec.decode(&bytes)?;
+        let init = match input {
+            Input::Init(data) => data,
+            _ => {
+                let message = "expected the first message to be init".to_owned();
+                if let Ok(rejection) = codec.encode(&Output::Reject { message: message }) {
+                    let _ = client.push(rejection);
+                }
+                return Err(Error::UnexpectedState);
+            }
+        };
+
+        match builder.build(init) {
+            Ok(session) => {
+                let mut ctx = Context {
+                    client: client,
+                    session: session,
+                    codec: codec,
+                };
+                ctx.send(Output::Ready)?;
+                Ok(ctx)
+            }
+            Err(err) => {
+                let message = err.to_string();
+                if let Ok(rejection) = codec.encode(&Output::Reject { message: message }) {
+                    let _ = client.push(rejection);
+                }
+                Err(err)
+            }
         }
     }
 
-    pub fn recv(
-        &mut self,
-    ) -> Result<Input> {
-        let content = self.client.pull()?.ok_or(Error::ConnectionClosed)?;
-        debug!("Recv => {}", content);
-        let input = serde_json::from_str(&content)?;
-        Ok(input)
+    pub fn recv(&mut self) -> Result<Input> {
+        let bytes = self.client.pull()?.ok_or(Error::ConnectionClosed)?;
+        debug!("Recv => {} bytes", bytes.len());
+        self.codec.decode(&bytes)
     }
 
     pub fn send(&mut self, out: Output) -> Result<()> {
-        let content = serde_json::to_string(&out)?;
-        debug!("Send <= {}", content);
-        self.client.push(content).map_err(Error::from)
+        let bytes = self.codec.encode(&out)?;
+        debug!("Send <= {} bytes", bytes.len());
+        self.client.push(bytes).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// An in-memory `Flow` for exercising `Context` without a real
+    /// transport: `pull` drains a queue of pre-seeded inbound messages;
+    /// `push` records whatever was sent into a handle the test keeps, so
+    /// pushes made on an error path (where the `Flow` itself is dropped)
+    /// can still be asserted on.
+    struct FakeFlow {
+        inbound: VecDeque<Vec<u8>>,
+        outbound: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl FakeFlow {
+        fn new(inbound: Vec<Vec<u8>>) -> (Self, Arc<Mutex<Vec<Vec<u8>>>>) {
+            let outbound = Arc::new(Mutex::new(Vec::new()));
+            let flow = FakeFlow {
+                inbound: inbound.into_iter().collect(),
+                outbound: outbound.clone(),
+            };
+            (flow, outbound)
+        }
+    }
+
+    impl Flow for FakeFlow {
+        fn pull(&mut self) -> flow::Result<Option<Vec<u8>>> {
+            Ok(self.inbound.pop_front())
+        }
+
+        fn push(&mut self, content: Vec<u8>) -> flow::Result<()> {
+            self.outbound.lock().unwrap().push(content);
+            Ok(())
+        }
+    }
+
+    struct TestSession;
+    impl Session for TestSession {}
+
+    struct OkBuilder;
+    impl Builder<TestSession> for OkBuilder {
+        fn build(&self, _init: Value) -> Result<TestSession> {
+            Ok(TestSession)
+        }
+    }
+
+    struct RejectingBuilder;
+    impl Builder<TestSession> for RejectingBuilder {
+        fn build(&self, _init: Value) -> Result<TestSession> {
+            Err(Error::Rejected("not allowed".to_owned()))
+        }
+    }
+
+    fn sent_rejects(outbound: &Arc<Mutex<Vec<Vec<u8>>>>) -> Vec<Output> {
+        outbound
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bytes| ::serde_json::from_slice(bytes).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn non_init_first_message_returns_unexpected_state_and_sends_a_reject() {
+        let request = ::serde_json::to_vec(&Input::Next { id: 1 }).unwrap();
+        let (flow, outbound) = FakeFlow::new(vec![request]);
+
+        let err = Context::<TestSession, FakeFlow>::handshake(flow, &OkBuilder)
+            .err()
+            .expect("handshake should fail");
+
+        assert_eq!(err.to_string(), Error::UnexpectedState.to_string());
+        let sent = sent_rejects(&outbound);
+        assert_eq!(sent.len(), 1);
+        match sent[0] {
+            Output::Reject { .. } => {}
+            _ => panic!("expected a Reject"),
+        }
+    }
+
+    #[test]
+    fn builder_error_is_returned_and_sent_as_a_reject() {
+        let init = ::serde_json::to_vec(&Input::Init(Value::Null)).unwrap();
+        let (flow, outbound) = FakeFlow::new(vec![init]);
+
+        let err = Context::<TestSession, FakeFlow>::handshake(flow, &RejectingBuilder)
+            .err()
+            .expect("handshake should fail");
+
+        assert_eq!(err.to_string(), Error::Rejected("not allowed".to_owned()).to_string());
+        let sent = sent_rejects(&outbound);
+        assert_eq!(sent.len(), 1);
+        match sent[0] {
+            Output::Reject { ref message } => assert_eq!(message, "rejected: not allowed"),
+            _ => panic!("expected a Reject"),
+        }
     }
 }
@@ -0,0 +1,271 @@
+//! Routes multiplexed `Input` messages to the buffered task they belong
+//! to, keeping one slot per live `TaskId` on the connection, and drives
+//! the per-connection protocol loop that ties `Context`, `Tasks` and a
+//! `Dispatch` together.
+//!
+//! A `request` runs `dispatch` to completion synchronously and buffers
+//! every item it returns; `next`/`cancel` then page through (or drop)
+//! that buffer by id. Several ids can have buffered results outstanding
+//! at once, but `dispatch` itself is not interruptible and the loop
+//! processes one `Input` at a time, so a second `request` can't start
+//! running until the first's `dispatch` call returns — this is
+//! buffered-by-id pagination, not concurrent task execution.
+
+use std::collections::VecDeque;
+
+use slab::Slab;
+
+use cache::{self, CacheAdapter};
+use codec::Codec;
+use flow::Flow;
+use service::Service;
+use session::{self, Context, Input, Output, Session, TaskId, Value};
+
+/// A registry of the tasks currently running on one connection.
+///
+/// Ids are assigned by the client, not by `Tasks` itself, so `open`
+/// fails if the id is already in use rather than silently reusing the
+/// slot.
+pub struct Tasks<H> {
+    running: Slab<H>,
+    by_id: ::std::collections::HashMap<TaskId, usize>,
+}
+
+impl<H> Tasks<H> {
+    pub fn new() -> Self {
+        Tasks {
+            running: Slab::new(),
+            by_id: ::std::collections::HashMap::new(),
+        }
+    }
+
+    /// Registers a newly started task under `id`.
+    ///
+    /// Returns `false` without touching the registry if `id` is already
+    /// in use: ids must be unique among the tasks that are currently
+    /// live.
+    pub fn open(&mut self, id: TaskId, handle: H) -> bool {
+        if self.by_id.contains_key(&id) {
+            return false;
+        }
+        let slot = self.running.insert(handle);
+        self.by_id.insert(id, slot);
+        true
+    }
+
+    /// Looks up the handle for an in-flight task, e.g. to forward a
+    /// `next` to it.
+    pub fn get_mut(&mut self, id: TaskId) -> Option<&mut H> {
+        let slot = *self.by_id.get(&id)?;
+        self.running.get_mut(slot)
+    }
+
+    /// Cancels a task, freeing its slot. A `cancel` for an id that is
+    /// not (or no longer) running is a no-op, matching the protocol's
+    /// invariant that cancel is idempotent.
+    pub fn cancel(&mut self, id: TaskId) -> Option<H> {
+        self.close(id)
+    }
+
+    /// Frees the slot for a task that ran to completion.
+    pub fn close(&mut self, id: TaskId) -> Option<H> {
+        let slot = self.by_id.remove(&id)?;
+        Some(self.running.remove(slot))
+    }
+
+    pub fn is_running(&self, id: TaskId) -> bool {
+        self.by_id.contains_key(&id)
+    }
+}
+
+/// Looks a `request`'s `service`/`action` up and runs it, returning the
+/// items to stream back. Implemented by `Dispatcher` (which adds result
+/// caching); kept as its own trait so `run` doesn't need to know
+/// anything about caching.
+pub trait Dispatch: Send + Sync + 'static {
+    fn dispatch(&self, service: &str, action: &str, payload: &Value) -> session::Result<Vec<Value>>;
+}
+
+impl<D: Dispatch + ?Sized> Dispatch for ::std::sync::Arc<D> {
+    fn dispatch(&self, service: &str, action: &str, payload: &Value) -> session::Result<Vec<Value>> {
+        (**self).dispatch(service, action, payload)
+    }
+}
+
+/// Drives the per-connection protocol loop: receives `Input`, routes
+/// `next`/`cancel` to the buffered task it addresses via `Tasks`, runs
+/// each incoming `request` through `dispatch` and opens a task for its
+/// results, and tags every `Output`
+/// it sends back with that task's id. Returns once the peer closes the
+/// connection.
+///
+/// `dispatch` runs to completion synchronously before its task is ever
+/// opened in `Tasks`, so `cancel` can only drop an already-buffered
+/// result set, never interrupt `dispatch` itself, and the loop can't
+/// start a second `request`'s `dispatch` call until the first returns.
+pub fn run<T, R, C, D>(mut ctx: Context<T, R, C>, dispatch: D) -> session::Result<()>
+where
+    T: Session,
+    R: Flow,
+    C: Codec,
+    D: Dispatch,
+{
+    let mut tasks: Tasks<VecDeque<Value>> = Tasks::new();
+
+    loop {
+        let input = match ctx.recv() {
+            Ok(input) => input,
+            Err(session::Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        match input {
+            Input::Init(_) => return Err(session::Error::UnexpectedState),
+            Input::Request {
+                id,
+                service,
+                action,
+                payload,
+            } => match dispatch.dispatch(&service, &action, &payload) {
+                Ok(items) => {
+                    tasks.open(id, VecDeque::from(items));
+                    send_next(&mut ctx, &mut tasks, id)?;
+                }
+                Err(err) => {
+                    let message = err.to_string();
+                    ctx.send(Output::Fail { id: id, message: message })?;
+                }
+            },
+            Input::Next { id } => send_next(&mut ctx, &mut tasks, id)?,
+            Input::Cancel { id } => {
+                tasks.cancel(id);
+            }
+        }
+    }
+}
+
+/// Sends the next buffered item for `id`, or `Done` (and frees the
+/// slot) once the task is drained. A `next` for an id that isn't
+/// running (never opened, already done, or canceled) sends nothing,
+/// matching `cancel`'s no-op-on-unknown-id invariant.
+fn send_next<T, R, C>(
+    ctx: &mut Context<T, R, C>,
+    tasks: &mut Tasks<VecDeque<Value>>,
+    id: TaskId,
+) -> session::Result<()>
+where
+    T: Session,
+    R: Flow,
+    C: Codec,
+{
+    let item = match tasks.get_mut(id) {
+        Some(queue) => queue.pop_front(),
+        None => return Ok(()),
+    };
+
+    match item {
+        Some(payload) => ctx.send(Output::Item { id: id, payload: payload }),
+        None => {
+            tasks.close(id);
+            ctx.send(Output::Done { id: id })
+        }
+    }
+}
+
+/// Looks a `Request` up by service name and runs it, consulting `cache`
+/// first when the target action declares a TTL and populating it on a
+/// miss.
+pub struct Dispatcher<A: CacheAdapter> {
+    services: Vec<Box<dyn Service>>,
+    cache: A,
+}
+
+impl<A: CacheAdapter> Dispatcher<A> {
+    pub fn new(cache: A) -> Self {
+        Dispatcher {
+            services: Vec::new(),
+            cache: cache,
+        }
+    }
+
+    pub fn register(&mut self, service: Box<dyn Service>) {
+        self.services.push(service);
+    }
+
+    /// Runs `service_name`/`action`, returning the items to stream back
+    /// to the client as `Output::Item`s before the closing `Done`.
+    pub fn dispatch(
+        &self,
+        service_name: &str,
+        action: &str,
+        payload: &Value,
+    ) -> session::Result<Vec<Value>> {
+        let service = self.services
+            .iter()
+            .find(|service| service.name() == service_name)
+            .ok_or(session::Error::UnexpectedState)?;
+
+        let ttl = service.cache_ttl(action);
+        let key = ttl.map(|_| cache::key_for(service_name, action, payload));
+
+        if let Some(ref key) = key {
+            if let Some(cached) = self.cache.get(key) {
+                if let Ok(items) = ::serde_json::from_slice(&cached) {
+                    return Ok(items);
+                }
+            }
+        }
+
+        let items = service.call(action, payload.clone())?;
+
+        if let Some(ttl) = ttl {
+            if let Some(key) = key {
+                if let Ok(encoded) = ::serde_json::to_vec(&items) {
+                    self.cache.set(&key, encoded, Some(ttl));
+                }
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Purges cached results whose key matches `pattern`, so a mutating
+    /// action can invalidate the reads it affects.
+    pub fn invalidate(&self, pattern: &str) {
+        self.cache.invalidate(pattern);
+    }
+}
+
+impl<A: CacheAdapter> Dispatch for Dispatcher<A> {
+    fn dispatch(&self, service: &str, action: &str, payload: &Value) -> session::Result<Vec<Value>> {
+        Dispatcher::dispatch(self, service, action, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_rejects_a_duplicate_live_id() {
+        let mut tasks: Tasks<u32> = Tasks::new();
+        assert!(tasks.open(1, 10));
+        assert!(!tasks.open(1, 20));
+        assert_eq!(*tasks.get_mut(1).unwrap(), 10);
+    }
+
+    #[test]
+    fn cancel_on_an_unknown_id_is_a_no_op() {
+        let mut tasks: Tasks<u32> = Tasks::new();
+        assert_eq!(tasks.cancel(42), None);
+    }
+
+    #[test]
+    fn close_frees_the_slot_so_the_id_can_be_reused() {
+        let mut tasks: Tasks<u32> = Tasks::new();
+        tasks.open(1, 10);
+        assert_eq!(tasks.close(1), Some(10));
+        assert!(!tasks.is_running(1));
+        assert!(tasks.open(1, 20));
+    }
+}